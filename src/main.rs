@@ -1,4 +1,19 @@
-use std::{any::Any, cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use num_rational::Rational64;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// The fixed seed used to (re-)initialize `State::rng` on every `compile` iteration.
+///
+/// `compile` re-runs the program from scratch up to five times looking for a fixpoint, so the
+/// RNG must produce the exact same sequence of draws each time `initialize` is called, otherwise
+/// `out == new_out` would never hold and the loop would never converge.
+const RNG_SEED: u64 = 0x5EED;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 struct Variable(String);
@@ -17,6 +32,14 @@ enum Ast {
     Variable(Variable),
     Const(Value),
     Cond(Box<Ast>, Box<Ast>, Box<Ast>),
+    Tuple(Vec<Ast>),
+    List(Vec<Ast>),
+    Perform(String, Box<Ast>),
+    Handle {
+        body: Box<Ast>,
+        op: String,
+        handler: Box<Ast>,
+    },
 }
 
 #[derive(Clone)]
@@ -25,9 +48,23 @@ enum Value {
     BuiltinFunction(Rc<dyn Fn(Value, &mut State) -> Result<Value, Error>>),
     BuiltinValue(Rc<dyn Any>),
     Function(Rc<RefCell<Context>>, Variable, Box<Ast>),
+    Thunk(Rc<RefCell<ThunkState>>),
     Bool(bool),
     Int(i64),
+    Ratio(Rational64),
     String(String),
+    Tup(Vec<Value>),
+    List(Rc<Vec<Value>>),
+}
+
+/// The state of a lazily-bound `Application` argument: either still waiting to be interpreted in
+/// its captured context, mid-force (a "black hole", guarding against a thunk that forces itself),
+/// or already interpreted and memoized.
+#[derive(Clone)]
+enum ThunkState {
+    Unevaluated(Ast, Rc<RefCell<Context>>),
+    BlackHole,
+    Evaluated(Value),
 }
 
 #[derive(Clone)]
@@ -35,11 +72,30 @@ enum Error {
     NotInScope(Variable),
     NotACallableValue(Value),
     NotABoolValue(Value),
+    NotACollectionValue(Value),
+    NotANumberValue(Value),
+    NotATupleValue(Value),
+    NotAStringValue(Value),
+    IndexOutOfBounds(i64),
+    EmptyCollection,
+    BlackHole,
+    UnhandledEffect(String),
+}
+
+/// A handler installed by `Ast::Handle`, matched against `Ast::Perform`'s operation name. `value`
+/// is the two-argument curried function `payload -> resumption -> result` that the handler body
+/// evaluated to.
+#[derive(Clone)]
+struct Handler {
+    op: String,
+    value: Value,
 }
 
 struct State {
     content: Vec<String>,
     document_size: usize,
+    rng: StdRng,
+    handlers: Vec<Handler>,
 }
 
 #[derive(Clone)]
@@ -55,19 +111,12 @@ impl Context {
         assert!(ret.is_none());
     }
 
-    pub fn with_var<T>(
-        &mut self,
-        var: &Variable,
-        value: Value,
-        func: impl FnOnce(&mut Context) -> T,
-    ) -> T {
-        let maybe_save = self.0.insert(var.clone(), value);
-        let out = func(self);
-        match maybe_save {
-            Some(save) => self.0.insert(var.clone(), save),
-            None => self.0.remove(var),
-        };
-        out
+    /// Binds `var` to `value`, silently overwriting any existing binding of the same name. Unlike
+    /// `insert`, this is for binding a lambda's parameter into its (already-cloned) closure
+    /// context, where shadowing an outer variable of the same name is legitimate (e.g. the inner
+    /// `\x` of `(\x. \x. x)`), not a bug to assert against.
+    pub fn rebind(&mut self, var: Variable, value: Value) {
+        self.0.insert(var, value);
     }
 
     pub fn lookup(&self, variable: &Variable) -> Option<&Value> {
@@ -75,47 +124,315 @@ impl Context {
     }
 }
 
+/// The "rest of the program" at some point during interpretation, reified as an owned closure so
+/// that `Ast::Perform` can hand it to a handler as a resumable `Value::BuiltinFunction`. Matches
+/// `Value::BuiltinFunction`'s own signature so a captured continuation can be wrapped in a `Value`
+/// with no further adapting.
+type Continuation = Rc<dyn Fn(Value, &mut State) -> Result<Value, Error>>;
+
+fn identity_continuation() -> Continuation {
+    Rc::new(|value, _state| Ok(value))
+}
+
+/// Interprets `ast` for its effect on `state` and ultimate value, without exposing a continuation
+/// to callers that just want a plain result (builtins, `force`, top-level `compile`).
 fn interpret(ast: &Ast, context: &mut Context, state: &mut State) -> Result<Value, Error> {
+    interpret_cps(
+        ast.clone(),
+        Rc::new(RefCell::new(context.clone())),
+        state,
+        identity_continuation(),
+    )
+}
+
+/// Continuation-passing-style interpreter: instead of returning a `Value` directly, every case
+/// hands its result to `k`. This makes "the rest of the computation" at an `Ast::Perform` a plain
+/// `Value` that a handler can store, call zero, one, or many times, or discard outright (an
+/// abortive handler). That continuation is not cut off at the enclosing `Ast::Handle`: it runs on
+/// to the nearest point that began with `identity_continuation` (an `interpret` call, or a tuple or
+/// list element), and whatever the handler returns becomes the result at that point.
+fn interpret_cps(
+    ast: Ast,
+    context: Rc<RefCell<Context>>,
+    state: &mut State,
+    k: Continuation,
+) -> Result<Value, Error> {
     match ast {
-        Ast::Lambda(bound_var, body) => Ok(Value::Function(
-            Rc::new(RefCell::new(context.clone())),
-            bound_var.clone(),
-            body.clone(),
-        )),
-        Ast::Fix => Ok(Value::Fix),
+        Ast::Lambda(bound_var, body) => k(Value::Function(context.clone(), bound_var, body), state),
+        Ast::Fix => k(Value::Fix, state),
         Ast::Application(f, x) => {
-            let arg = interpret(x, context, state)?;
-            match interpret(f, context, state)? {
-                Value::BuiltinFunction(func) => func(arg, state),
-                Value::Function(closure_context, bound_var, body) => closure_context
-                    .borrow_mut()
-                    .with_var(&bound_var, arg, |context| interpret(&body, context, state)),
-                Value::Fix => interpret(
-                    &Ast::Application(Box::new(Ast::Const(arg)), Box::new(ast.clone())),
-                    context,
-                    state,
-                ),
-                val @ (Value::BuiltinValue(_)
-                | Value::Bool(_)
-                | Value::Int(_)
-                | Value::String(_)) => Err(Error::NotACallableValue(val)),
+            let original = Ast::Application(f.clone(), x.clone());
+            let arg = Value::Thunk(Rc::new(RefCell::new(ThunkState::Unevaluated(
+                *x,
+                context.clone(),
+            ))));
+            let context_for_fix = context.clone();
+            interpret_cps(*f, context, state, {
+                let k = k.clone();
+                Rc::new(move |f_value, state: &mut State| match force(f_value, state)? {
+                    Value::BuiltinFunction(func) => {
+                        let arg = force(arg.clone(), state)?;
+                        let result = func(arg, state)?;
+                        k(result, state)
+                    }
+                    Value::Function(closure_context, bound_var, body) => {
+                        let mut new_context = closure_context.borrow().clone();
+                        new_context.rebind(bound_var, arg.clone());
+                        interpret_cps(*body, Rc::new(RefCell::new(new_context)), state, k.clone())
+                    }
+                    Value::Fix => {
+                        let refixed = Ast::Application(
+                            Box::new(Ast::Const(arg.clone())),
+                            Box::new(original.clone()),
+                        );
+                        interpret_cps(refixed, context_for_fix.clone(), state, k.clone())
+                    }
+                    val @ (Value::BuiltinValue(_)
+                    | Value::Bool(_)
+                    | Value::Int(_)
+                    | Value::Ratio(_)
+                    | Value::String(_)
+                    | Value::Tup(_)
+                    | Value::List(_)
+                    | Value::Thunk(_)) => Err(Error::NotACallableValue(val)),
+                })
+            })
+        }
+        Ast::Variable(var) => {
+            let looked_up = context.borrow().lookup(&var).cloned();
+            match looked_up {
+                Some(val) => k(val, state),
+                None => Err(Error::NotInScope(var)),
             }
         }
-        Ast::Variable(var) => match context.lookup(var) {
-            Some(out) => Ok(out.clone()),
-            None => Err(Error::NotInScope(var.clone())),
-        },
-        Ast::Const(val) => Ok(val.clone()),
-        Ast::Cond(cond, then, r#else) => match interpret(cond, context, state)? {
-            Value::Bool(b) => interpret(if b { then } else { r#else }, context, state),
+        Ast::Const(val) => k(val, state),
+        Ast::Cond(cond, then, r#else) => {
+            let context_for_branch = context.clone();
+            interpret_cps(*cond, context, state, {
+                let k = k.clone();
+                Rc::new(move |cond_value, state: &mut State| match force(cond_value, state)? {
+                    Value::Bool(b) => {
+                        let branch = if b { (*then).clone() } else { (*r#else).clone() };
+                        interpret_cps(branch, context_for_branch.clone(), state, k.clone())
+                    }
+                    val @ (Value::BuiltinFunction(_)
+                    | Value::Fix
+                    | Value::BuiltinValue(_)
+                    | Value::Function(_, _, _)
+                    | Value::Thunk(_)
+                    | Value::Int(_)
+                    | Value::Ratio(_)
+                    | Value::String(_)
+                    | Value::Tup(_)
+                    | Value::List(_)) => Err(Error::NotABoolValue(val)),
+                })
+            })
+        }
+        Ast::Tuple(elems) => {
+            let values = elems
+                .into_iter()
+                .map(|elem| interpret_cps(elem, context.clone(), state, identity_continuation()))
+                .collect::<Result<Vec<_>, _>>()?;
+            k(Value::Tup(values), state)
+        }
+        Ast::List(elems) => {
+            let values = elems
+                .into_iter()
+                .map(|elem| interpret_cps(elem, context.clone(), state, identity_continuation()))
+                .collect::<Result<Vec<_>, _>>()?;
+            k(Value::List(Rc::new(values)), state)
+        }
+        Ast::Perform(op, arg) => {
+            let context_for_dispatch = context.clone();
+            interpret_cps(*arg, context, state, {
+                let k = k.clone();
+                Rc::new(move |payload, state: &mut State| {
+                    let idx = state
+                        .handlers
+                        .iter()
+                        .rposition(|handler| handler.op == op)
+                        .ok_or_else(|| Error::UnhandledEffect(op.clone()))?;
+                    // Remove the matched handler, and everything installed after it, so that the
+                    // handler body runs outside its own dynamic extent; `resumption` reinstalls
+                    // exactly these when (and if) it is called, so a resumed computation still
+                    // sees the handlers it saw when `perform` was evaluated ("deep" handling).
+                    let removed = state.handlers.split_off(idx);
+                    let handler_value = removed[0].value.clone();
+                    let resumption = {
+                        let removed = removed.clone();
+                        let k = k.clone();
+                        Value::BuiltinFunction(Rc::new(move |resume_value, state: &mut State| {
+                            // Reinstall the removed handlers only for the extent of this one
+                            // call, then truncate back down: `resumption` may be called many
+                            // times (multi-shot), and each call must see its own clean copy of the
+                            // dynamic extent instead of piling another copy onto the stack.
+                            let pre_resume_len = state.handlers.len();
+                            state.handlers.extend(removed.clone());
+                            let result = k(resume_value, state);
+                            state.handlers.truncate(pre_resume_len);
+                            result
+                        }))
+                    };
+                    let call = Ast::Application(
+                        Box::new(Ast::Application(
+                            Box::new(Ast::Const(handler_value)),
+                            Box::new(Ast::Const(payload)),
+                        )),
+                        Box::new(Ast::Const(resumption)),
+                    );
+                    interpret_cps(call, context_for_dispatch.clone(), state, identity_continuation())
+                })
+            })
+        }
+        Ast::Handle { body, op, handler } => {
+            let handler_value =
+                interpret_cps(*handler, context.clone(), state, identity_continuation())?;
+            state.handlers.push(Handler {
+                op,
+                value: handler_value,
+            });
+            let k_after = {
+                let k = k.clone();
+                Rc::new(move |value, state: &mut State| {
+                    state.handlers.pop();
+                    k(value, state)
+                })
+            };
+            interpret_cps(*body, context, state, k_after)
+        }
+    }
+}
+
+/// Resolves a `Value::Thunk` to the `Value` it represents, interpreting it at most once and
+/// memoizing the result in place. Forcing a thunk that is already being forced (i.e. a thunk
+/// whose own evaluation forces itself) reports `Error::BlackHole` instead of recursing forever.
+/// A non-productive `Fix` is not caught this way: each unfolding re-interprets the `Fix`
+/// application and so forces fresh thunks rather than the one already being forced.
+fn force(value: Value, state: &mut State) -> Result<Value, Error> {
+    let cell = match value {
+        Value::Thunk(cell) => cell,
+        other => return Ok(other),
+    };
+
+    // Bound to a `let` (rather than matched on directly) so the `borrow_mut()` temporary is
+    // dropped before the arms run; matching on it directly would keep it alive for the whole
+    // match (it's the scrutinee) and the `Evaluated` arm's own `borrow_mut()` would then panic.
+    let previous = std::mem::replace(&mut *cell.borrow_mut(), ThunkState::BlackHole);
+    let to_evaluate = match previous {
+        ThunkState::Evaluated(val) => {
+            *cell.borrow_mut() = ThunkState::Evaluated(val.clone());
+            return Ok(val);
+        }
+        ThunkState::Unevaluated(ast, closure_context) => (ast, closure_context),
+        ThunkState::BlackHole => return Err(Error::BlackHole),
+    };
+
+    let (ast, closure_context) = to_evaluate;
+    let result = interpret(&ast, &mut closure_context.borrow_mut(), state)?;
+    let result = force(result, state)?;
+    *cell.borrow_mut() = ThunkState::Evaluated(result.clone());
+    Ok(result)
+}
+
+/// Coerces a `Value` into an indexable sequence of elements, accepted by `index`, `length` and
+/// `choose`. Both `List` and `Tup` qualify.
+fn expect_collection(value: Value) -> Result<Rc<Vec<Value>>, Error> {
+    match value {
+        Value::List(items) => Ok(items),
+        Value::Tup(items) => Ok(Rc::new(items)),
+        other => Err(Error::NotACollectionValue(other)),
+    }
+}
+
+/// Coerces a `Value` into a `Value::List`, for builtins (`cons`, `concat`) that only make sense
+/// for the homogeneous, growable collection and not for fixed-arity tuples.
+fn expect_list(value: Value) -> Result<Rc<Vec<Value>>, Error> {
+    match value {
+        Value::List(items) => Ok(items),
+        other => Err(Error::NotACollectionValue(other)),
+    }
+}
+
+/// Coerces a `Value` into a plain `i64`, for builtins that index into a collection.
+fn expect_int(value: Value) -> Result<i64, Error> {
+    match value {
+        Value::Int(i) => Ok(i),
+        other => Err(Error::NotANumberValue(other)),
+    }
+}
+
+/// Decodes the collection expected by `weighted`: a list of 2-element `(weight, value)` tuples.
+fn expect_weighted_collection(value: Value) -> Result<Vec<(i64, Value)>, Error> {
+    expect_list(value)?
+        .iter()
+        .cloned()
+        .map(|item| match item {
+            Value::Tup(pair) if pair.len() == 2 => {
+                let mut pair = pair.into_iter();
+                let weight = pair.next().unwrap();
+                let value = pair.next().unwrap();
+                match weight {
+                    Value::Int(weight) => Ok((weight, value)),
+                    other => Err(Error::NotANumberValue(other)),
+                }
+            }
+            other => Err(Error::NotATupleValue(other)),
+        })
+        .collect()
+}
+
+/// Coerces a `Value` into the numeric tower, promoting `Int` into an integral `Ratio` so that
+/// arithmetic and comparison builtins can treat the two interchangeably.
+fn expect_numeric(value: Value) -> Result<Rational64, Error> {
+    match value {
+        Value::Int(i) => Ok(Rational64::from_integer(i)),
+        Value::Ratio(r) => Ok(r),
+        other => Err(Error::NotANumberValue(other)),
+    }
+}
+
+/// Curries a binary numeric builtin: `name a b` applies `op` to `a` and `b` once both have been
+/// supplied, one `Application` at a time, as is the convention for every builtin in this language.
+fn binary_numeric_builtin(
+    op: impl Fn(Rational64, Rational64) -> Value + Clone + 'static,
+) -> Value {
+    Value::BuiltinFunction(Rc::new(move |a, _state| {
+        let a = expect_numeric(a)?;
+        let op = op.clone();
+        Ok(Value::BuiltinFunction(Rc::new(move |b, _state| {
+            let b = expect_numeric(b)?;
+            Ok(op(a, b))
+        })))
+    }))
+}
+
+/// The handler installed for the `"content"` operation before any user code runs: pushes the
+/// performed string onto `state.content` and resumes with that same string, so `perform "content"
+/// s` behaves exactly like the old hard-wired `content` builtin, just expressed as an ordinary
+/// effect with a (replaceable) default handler instead of a special case in `interpret`.
+fn default_content_handler() -> Value {
+    Value::BuiltinFunction(Rc::new(|payload, state| {
+        let str = match force(payload, state)? {
+            Value::String(str) => str,
             val @ (Value::BuiltinFunction(_)
             | Value::Fix
             | Value::BuiltinValue(_)
             | Value::Function(_, _, _)
+            | Value::Thunk(_)
+            | Value::Bool(_)
             | Value::Int(_)
-            | Value::String(_)) => Err(Error::NotABoolValue(val)),
-        },
-    }
+            | Value::Ratio(_)
+            | Value::Tup(_)
+            | Value::List(_)) => return Err(Error::NotAStringValue(val)),
+        };
+        Ok(Value::BuiltinFunction(Rc::new(move |resume, state: &mut State| {
+            state.content.push(str.clone());
+            match resume {
+                Value::BuiltinFunction(resume) => resume(Value::String(str.clone()), state),
+                other => Err(Error::NotACallableValue(other)),
+            }
+        })))
+    }))
 }
 
 fn initialize(expected_document_size: usize) -> (Context, State) {
@@ -123,23 +440,20 @@ fn initialize(expected_document_size: usize) -> (Context, State) {
     let state = State {
         content: Vec::new(),
         document_size: expected_document_size,
+        rng: StdRng::seed_from_u64(RNG_SEED),
+        handlers: vec![Handler {
+            op: "content".to_string(),
+            value: default_content_handler(),
+        }],
     };
 
     context.insert(var!("true"), Value::Bool(true));
     context.insert(var!("false"), Value::Bool(false));
     context.insert(
         var!("content"),
-        Value::BuiltinFunction(Rc::new(|input, state| match input {
-            Value::String(str) => {
-                state.content.push(str.to_string());
-                Ok(Value::String(str))
-            }
-            Value::BuiltinFunction(_)
-            | Value::Fix
-            | Value::BuiltinValue(_)
-            | Value::Function(_, _, _)
-            | Value::Bool(_)
-            | Value::Int(_) => todo!(),
+        Value::BuiltinFunction(Rc::new(|input, state| {
+            let ast = Ast::Perform("content".to_string(), Box::new(Ast::Const(input)));
+            interpret(&ast, &mut Context::new(), state)
         })),
     );
     context.insert(
@@ -159,14 +473,328 @@ fn initialize(expected_document_size: usize) -> (Context, State) {
         Value::BuiltinFunction(Rc::new(|_input, state| {
             let k: i64 = state.content.len().try_into().unwrap();
             let n: i64 = state.document_size.try_into().unwrap();
-            Ok(Value::Int(k / n))
+            // `document_size` is whatever `initialize` was given, and that is 0 on `compile`'s
+            // first iteration (and in `builtin_scope`); `Rational64::new` panics on a zero
+            // denominator, so report "no progress yet" as 0 rather than aborting.
+            if n == 0 {
+                return Ok(Value::Ratio(Rational64::from_integer(0)));
+            }
+            Ok(Value::Ratio(Rational64::new(k, n)))
+        })),
+    );
+    context.insert(
+        var!("add"),
+        binary_numeric_builtin(|a, b| Value::Ratio(a + b)),
+    );
+    context.insert(
+        var!("sub"),
+        binary_numeric_builtin(|a, b| Value::Ratio(a - b)),
+    );
+    context.insert(
+        var!("mul"),
+        binary_numeric_builtin(|a, b| Value::Ratio(a * b)),
+    );
+    context.insert(
+        var!("div"),
+        binary_numeric_builtin(|a, b| Value::Ratio(a / b)),
+    );
+    context.insert(var!("lt"), binary_numeric_builtin(|a, b| Value::Bool(a < b)));
+    context.insert(var!("eq"), binary_numeric_builtin(|a, b| Value::Bool(a == b)));
+    context.insert(
+        var!("index"),
+        Value::BuiltinFunction(Rc::new(|collection, _state| {
+            let items = expect_collection(collection)?;
+            Ok(Value::BuiltinFunction(Rc::new(move |idx, _state| {
+                let idx = expect_int(idx)?;
+                let i: usize = idx.try_into().map_err(|_| Error::IndexOutOfBounds(idx))?;
+                items.get(i).cloned().ok_or(Error::IndexOutOfBounds(idx))
+            })))
+        })),
+    );
+    context.insert(
+        var!("length"),
+        Value::BuiltinFunction(Rc::new(|collection, _state| {
+            let items = expect_collection(collection)?;
+            Ok(Value::Int(items.len().try_into().unwrap()))
+        })),
+    );
+    context.insert(
+        var!("cons"),
+        Value::BuiltinFunction(Rc::new(|elem, _state| {
+            Ok(Value::BuiltinFunction(Rc::new(move |list, _state| {
+                let items = expect_list(list)?;
+                let mut new_items = Vec::with_capacity(items.len() + 1);
+                new_items.push(elem.clone());
+                new_items.extend(items.iter().cloned());
+                Ok(Value::List(Rc::new(new_items)))
+            })))
+        })),
+    );
+    context.insert(
+        var!("concat"),
+        Value::BuiltinFunction(Rc::new(|a, _state| {
+            Ok(Value::BuiltinFunction(Rc::new(move |b, _state| {
+                let mut items = (*expect_list(a.clone())?).clone();
+                items.extend(expect_list(b)?.iter().cloned());
+                Ok(Value::List(Rc::new(items)))
+            })))
+        })),
+    );
+    context.insert(
+        var!("choose"),
+        Value::BuiltinFunction(Rc::new(|input, state| {
+            let items = expect_collection(input)?;
+            if items.is_empty() {
+                return Err(Error::EmptyCollection);
+            }
+            let index = state.rng.gen_range(0..items.len());
+            Ok(items[index].clone())
+        })),
+    );
+    context.insert(
+        var!("weighted"),
+        Value::BuiltinFunction(Rc::new(|input, state| {
+            let items = expect_weighted_collection(input)?;
+            let total: i64 = items.iter().map(|(weight, _)| weight).sum();
+            if items.is_empty() || total <= 0 {
+                return Err(Error::EmptyCollection);
+            }
+            let mut pick = state.rng.gen_range(0..total);
+            for (weight, value) in items.iter() {
+                if pick < *weight {
+                    return Ok(value.clone());
+                }
+                pick -= weight;
+            }
+            unreachable!("pick should always land within the cumulative weights")
         })),
     );
 
     (context, state)
 }
 
-fn compile(ast: &Ast) -> Result<Vec<String>, Error> {
+/// The set of variables bound by `initialize` before any user code runs, i.e. the top-level scope
+/// that `analyze` starts from.
+fn builtin_scope() -> HashSet<Variable> {
+    let (context, _state) = initialize(0);
+    context.0.keys().cloned().collect()
+}
+
+/// Statically walks `ast` without executing any builtins, collecting every diagnostic it can find
+/// (unbound variables, applications whose head is obviously not callable, `Cond`s whose condition
+/// is obviously not a `Bool`) instead of stopping at the first one, the way `interpret` does.
+fn analyze(ast: &Ast, scope: &HashSet<Variable>) -> Vec<Error> {
+    let mut errors = Vec::new();
+    match ast {
+        Ast::Lambda(bound_var, body) => {
+            let mut inner_scope = scope.clone();
+            inner_scope.insert(bound_var.clone());
+            errors.extend(analyze(body, &inner_scope));
+        }
+        Ast::Fix => {}
+        Ast::Application(f, x) => {
+            if let Ast::Const(val @ (Value::Int(_) | Value::Bool(_) | Value::String(_))) =
+                f.as_ref()
+            {
+                errors.push(Error::NotACallableValue(val.clone()));
+            }
+            errors.extend(analyze(f, scope));
+            errors.extend(analyze(x, scope));
+        }
+        Ast::Variable(var) => {
+            if !scope.contains(var) {
+                errors.push(Error::NotInScope(var.clone()));
+            }
+        }
+        Ast::Const(_) => {}
+        Ast::Cond(cond, then, r#else) => {
+            if let Ast::Const(val) = cond.as_ref() {
+                if !matches!(val, Value::Bool(_)) {
+                    errors.push(Error::NotABoolValue(val.clone()));
+                }
+            }
+            errors.extend(analyze(cond, scope));
+            errors.extend(analyze(then, scope));
+            errors.extend(analyze(r#else, scope));
+        }
+        Ast::Tuple(elems) | Ast::List(elems) => {
+            for elem in elems {
+                errors.extend(analyze(elem, scope));
+            }
+        }
+        Ast::Perform(_, arg) => errors.extend(analyze(arg, scope)),
+        Ast::Handle { body, handler, .. } => {
+            errors.extend(analyze(body, scope));
+            errors.extend(analyze(handler, scope));
+        }
+    }
+    errors
+}
+
+/// Visits `ast` and every descendant reachable through nodes for which `visit` returned `true`,
+/// depth-first. Returning `false` from `visit` prunes that node's children without stopping the
+/// walk elsewhere, letting callers bail out of uninteresting subtrees early.
+fn walk(ast: &Ast, visit: &mut impl FnMut(&Ast) -> bool) {
+    if !visit(ast) {
+        return;
+    }
+    match ast {
+        Ast::Lambda(_, body) => walk(body, visit),
+        Ast::Fix | Ast::Variable(_) | Ast::Const(_) => {}
+        Ast::Application(f, x) => {
+            walk(f, visit);
+            walk(x, visit);
+        }
+        Ast::Cond(cond, then, r#else) => {
+            walk(cond, visit);
+            walk(then, visit);
+            walk(r#else, visit);
+        }
+        Ast::Tuple(elems) | Ast::List(elems) => {
+            for elem in elems {
+                walk(elem, visit);
+            }
+        }
+        Ast::Perform(_, arg) => walk(arg, visit),
+        Ast::Handle { body, handler, .. } => {
+            walk(body, visit);
+            walk(handler, visit);
+        }
+    }
+}
+
+/// A value that is always safe to duplicate in place of a variable: interpreting a `Const` never
+/// has side effects, but we still only fold values cheap and inert enough that duplicating the
+/// AST node is clearly free (unlike, say, a `Thunk` or a `Function` closing over state).
+fn is_pure_literal(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Bool(_) | Value::Int(_) | Value::Ratio(_) | Value::String(_)
+    )
+}
+
+/// Counts the free (i.e. not shadowed by a nested `Lambda` binding the same name) occurrences of
+/// `var` in `ast`.
+fn count_occurrences(var: &Variable, ast: &Ast) -> usize {
+    match ast {
+        Ast::Lambda(bound_var, body) => {
+            if bound_var == var {
+                0
+            } else {
+                count_occurrences(var, body)
+            }
+        }
+        Ast::Fix | Ast::Const(_) => 0,
+        Ast::Application(f, x) => count_occurrences(var, f) + count_occurrences(var, x),
+        Ast::Variable(v) => usize::from(v == var),
+        Ast::Cond(cond, then, r#else) => {
+            count_occurrences(var, cond) + count_occurrences(var, then) + count_occurrences(var, r#else)
+        }
+        Ast::Tuple(elems) | Ast::List(elems) => {
+            elems.iter().map(|elem| count_occurrences(var, elem)).sum()
+        }
+        Ast::Perform(_, arg) => count_occurrences(var, arg),
+        Ast::Handle { body, handler, .. } => {
+            count_occurrences(var, body) + count_occurrences(var, handler)
+        }
+    }
+}
+
+/// Replaces every free occurrence of `var` in `ast` with `replacement`.
+fn substitute(ast: Ast, var: &Variable, replacement: &Ast) -> Ast {
+    match ast {
+        Ast::Lambda(bound_var, body) if &bound_var == var => Ast::Lambda(bound_var, body),
+        Ast::Lambda(bound_var, body) => {
+            Ast::Lambda(bound_var, Box::new(substitute(*body, var, replacement)))
+        }
+        Ast::Fix => Ast::Fix,
+        Ast::Application(f, x) => Ast::Application(
+            Box::new(substitute(*f, var, replacement)),
+            Box::new(substitute(*x, var, replacement)),
+        ),
+        Ast::Variable(v) if &v == var => replacement.clone(),
+        Ast::Variable(v) => Ast::Variable(v),
+        Ast::Const(val) => Ast::Const(val),
+        Ast::Cond(cond, then, r#else) => Ast::Cond(
+            Box::new(substitute(*cond, var, replacement)),
+            Box::new(substitute(*then, var, replacement)),
+            Box::new(substitute(*r#else, var, replacement)),
+        ),
+        Ast::Tuple(elems) => Ast::Tuple(
+            elems
+                .into_iter()
+                .map(|elem| substitute(elem, var, replacement))
+                .collect(),
+        ),
+        Ast::List(elems) => Ast::List(
+            elems
+                .into_iter()
+                .map(|elem| substitute(elem, var, replacement))
+                .collect(),
+        ),
+        Ast::Perform(op, arg) => Ast::Perform(op, Box::new(substitute(*arg, var, replacement))),
+        Ast::Handle { body, op, handler } => Ast::Handle {
+            body: Box::new(substitute(*body, var, replacement)),
+            op,
+            handler: Box::new(substitute(*handler, var, replacement)),
+        },
+    }
+}
+
+/// Constant-folds the AST: collapses `Cond` on a literal `Bool` into the taken branch, and
+/// beta-reduces an `Application` of a `Lambda` to a pure literal argument used at most once.
+fn optimize(ast: Ast) -> Ast {
+    match ast {
+        Ast::Lambda(var, body) => Ast::Lambda(var, Box::new(optimize(*body))),
+        Ast::Fix => Ast::Fix,
+        Ast::Application(f, x) => {
+            let f = optimize(*f);
+            let x = optimize(*x);
+            match (f, x) {
+                (Ast::Lambda(var, body), Ast::Const(val))
+                    if is_pure_literal(&val) && count_occurrences(&var, &body) <= 1 =>
+                {
+                    substitute(*body, &var, &Ast::Const(val))
+                }
+                (f, x) => Ast::Application(Box::new(f), Box::new(x)),
+            }
+        }
+        Ast::Variable(var) => Ast::Variable(var),
+        Ast::Const(val) => Ast::Const(val),
+        Ast::Cond(cond, then, r#else) => {
+            let cond = optimize(*cond);
+            let then = optimize(*then);
+            let r#else = optimize(*r#else);
+            match cond {
+                Ast::Const(Value::Bool(b)) => {
+                    if b {
+                        then
+                    } else {
+                        r#else
+                    }
+                }
+                cond => Ast::Cond(Box::new(cond), Box::new(then), Box::new(r#else)),
+            }
+        }
+        Ast::Tuple(elems) => Ast::Tuple(elems.into_iter().map(optimize).collect()),
+        Ast::List(elems) => Ast::List(elems.into_iter().map(optimize).collect()),
+        Ast::Perform(op, arg) => Ast::Perform(op, Box::new(optimize(*arg))),
+        Ast::Handle { body, op, handler } => Ast::Handle {
+            body: Box::new(optimize(*body)),
+            op,
+            handler: Box::new(optimize(*handler)),
+        },
+    }
+}
+
+fn compile(ast: &Ast) -> Result<Vec<String>, Vec<Error>> {
+    let errors = analyze(ast, &builtin_scope());
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let ast = &optimize(ast.clone());
+
     let mut document_size = 0;
     let mut out = None;
     for i in 0..5 {
@@ -174,7 +802,7 @@ fn compile(ast: &Ast) -> Result<Vec<String>, Error> {
 
         let (mut context, mut state) = initialize(document_size);
 
-        interpret(ast, &mut context, &mut state)?;
+        interpret(ast, &mut context, &mut state).map_err(|err| vec![err])?;
 
         let new_out = Some(state.content);
         if out == new_out {
@@ -190,3 +818,224 @@ fn compile(ast: &Ast) -> Result<Vec<String>, Error> {
 fn main() {
     println!("Hello, world!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str) -> Variable {
+        Variable(name.to_string())
+    }
+
+    /// `Error` wraps `Value`, which in turn holds non-`Debug` trait objects (`Rc<dyn Fn>`,
+    /// `Rc<dyn Any>`), so `Result::unwrap` isn't available here; this is the test-only stand-in.
+    fn expect_ok(result: Result<Value, Error>) -> Value {
+        match result {
+            Ok(value) => value,
+            Err(_) => panic!("expected Ok(_), got an Err"),
+        }
+    }
+
+    #[test]
+    fn force_memoizes_thunk_evaluation() {
+        let (_, mut state) = initialize(0);
+        let ast = Ast::Perform(
+            "content".to_string(),
+            Box::new(Ast::Const(Value::String("hi".to_string()))),
+        );
+        let thunk = Value::Thunk(Rc::new(RefCell::new(ThunkState::Unevaluated(
+            ast,
+            Rc::new(RefCell::new(Context::new())),
+        ))));
+
+        expect_ok(force(thunk.clone(), &mut state));
+        expect_ok(force(thunk, &mut state));
+
+        // If `force` re-evaluated the thunk on the second call instead of returning the memoized
+        // result, "hi" would have been performed (and pushed to `content`) twice.
+        assert_eq!(state.content, vec!["hi".to_string()]);
+    }
+
+    #[test]
+    fn force_reports_black_hole_instead_of_recursing_forever() {
+        let (_, mut state) = initialize(0);
+        let thunk = Value::Thunk(Rc::new(RefCell::new(ThunkState::BlackHole)));
+
+        assert!(matches!(force(thunk, &mut state), Err(Error::BlackHole)));
+    }
+
+    #[test]
+    fn choose_is_deterministic_under_the_fixed_seed() {
+        let list = Ast::List(vec![
+            Ast::Const(Value::Int(1)),
+            Ast::Const(Value::Int(2)),
+            Ast::Const(Value::Int(3)),
+        ]);
+        let ast = Ast::Application(Box::new(Ast::Variable(var!("choose"))), Box::new(list));
+
+        let (mut context_a, mut state_a) = initialize(0);
+        let (mut context_b, mut state_b) = initialize(0);
+        let a = expect_ok(interpret(&ast, &mut context_a, &mut state_a));
+        let b = expect_ok(interpret(&ast, &mut context_b, &mut state_b));
+
+        assert!(matches!((a, b), (Value::Int(a), Value::Int(b)) if a == b));
+    }
+
+    #[test]
+    fn weighted_never_picks_a_zero_weight_item() {
+        let list = Ast::List(vec![
+            Ast::Tuple(vec![
+                Ast::Const(Value::Int(1)),
+                Ast::Const(Value::String("a".to_string())),
+            ]),
+            Ast::Tuple(vec![
+                Ast::Const(Value::Int(0)),
+                Ast::Const(Value::String("b".to_string())),
+            ]),
+        ]);
+        let ast = Ast::Application(Box::new(Ast::Variable(var!("weighted"))), Box::new(list));
+        let (mut context, mut state) = initialize(0);
+
+        let result = expect_ok(interpret(&ast, &mut context, &mut state));
+
+        assert!(matches!(result, Value::String(s) if s == "a"));
+    }
+
+    #[test]
+    fn optimize_beta_reduces_a_single_use_literal_argument() {
+        let ast = Ast::Application(
+            Box::new(Ast::Lambda(var("x"), Box::new(Ast::Variable(var("x"))))),
+            Box::new(Ast::Const(Value::Int(42))),
+        );
+
+        assert!(matches!(optimize(ast), Ast::Const(Value::Int(42))));
+    }
+
+    #[test]
+    fn optimize_folds_cond_on_a_literal_bool() {
+        let ast = Ast::Cond(
+            Box::new(Ast::Const(Value::Bool(false))),
+            Box::new(Ast::Const(Value::Int(1))),
+            Box::new(Ast::Const(Value::Int(2))),
+        );
+
+        assert!(matches!(optimize(ast), Ast::Const(Value::Int(2))));
+    }
+
+    #[test]
+    fn analyze_collects_every_error_instead_of_stopping_at_the_first() {
+        let ast = Ast::Tuple(vec![
+            Ast::Variable(var("undefined_one")),
+            Ast::Variable(var("undefined_two")),
+        ]);
+
+        let errors = analyze(&ast, &builtin_scope());
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|err| matches!(err, Error::NotInScope(_))));
+    }
+
+    #[test]
+    fn custom_handler_can_intercept_and_resume_a_content_effect() {
+        let resume_with_payload = Ast::Application(
+            Box::new(Ast::Variable(var("resume"))),
+            Box::new(Ast::Variable(var("payload"))),
+        );
+        let handler = Ast::Lambda(
+            var("payload"),
+            Box::new(Ast::Lambda(var("resume"), Box::new(resume_with_payload))),
+        );
+        let ast = Ast::Handle {
+            body: Box::new(Ast::Perform(
+                "content".to_string(),
+                Box::new(Ast::Const(Value::String("hi".to_string()))),
+            )),
+            op: "content".to_string(),
+            handler: Box::new(handler),
+        };
+        let (mut context, mut state) = initialize(0);
+
+        let result = expect_ok(interpret(&ast, &mut context, &mut state));
+
+        assert!(matches!(result, Value::String(s) if s == "hi"));
+    }
+
+    #[test]
+    fn nested_lambda_parameter_shadowing_does_not_panic() {
+        // ((\x. \x. x) 1) 2 should evaluate to 2, the inner `x`.
+        let inner = Ast::Lambda(var("x"), Box::new(Ast::Variable(var("x"))));
+        let outer = Ast::Lambda(var("x"), Box::new(inner));
+        let ast = Ast::Application(
+            Box::new(Ast::Application(
+                Box::new(outer),
+                Box::new(Ast::Const(Value::Int(1))),
+            )),
+            Box::new(Ast::Const(Value::Int(2))),
+        );
+        let (mut context, mut state) = initialize(0);
+
+        let result = expect_ok(interpret(&ast, &mut context, &mut state));
+        let result = expect_ok(force(result, &mut state));
+
+        assert!(matches!(result, Value::Int(2)));
+    }
+
+    #[test]
+    fn percent_does_not_panic_when_document_size_is_unknown() {
+        let ast = Ast::Application(
+            Box::new(Ast::Variable(var!("percent"))),
+            Box::new(Ast::Const(Value::Bool(true))),
+        );
+        let (mut context, mut state) = initialize(0);
+
+        let result = expect_ok(interpret(&ast, &mut context, &mut state));
+
+        assert!(matches!(result, Value::Ratio(r) if r == Rational64::from_integer(0)));
+    }
+
+    #[test]
+    fn content_effect_with_a_non_string_payload_errors_instead_of_panicking() {
+        let ast = Ast::Application(
+            Box::new(Ast::Variable(var!("content"))),
+            Box::new(Ast::Const(Value::Int(5))),
+        );
+        let (mut context, mut state) = initialize(0);
+
+        let result = interpret(&ast, &mut context, &mut state);
+
+        assert!(matches!(result, Err(Error::NotAStringValue(Value::Int(5)))));
+    }
+
+    #[test]
+    fn resumption_can_be_invoked_more_than_once_without_leaking_handlers() {
+        let resume_with_payload = Ast::Application(
+            Box::new(Ast::Variable(var("resume"))),
+            Box::new(Ast::Variable(var("payload"))),
+        );
+        let handler = Ast::Lambda(
+            var("payload"),
+            Box::new(Ast::Lambda(
+                var("resume"),
+                Box::new(Ast::Tuple(vec![
+                    resume_with_payload.clone(),
+                    resume_with_payload,
+                ])),
+            )),
+        );
+        let ast = Ast::Handle {
+            body: Box::new(Ast::Perform(
+                "content".to_string(),
+                Box::new(Ast::Const(Value::String("x".to_string()))),
+            )),
+            op: "content".to_string(),
+            handler: Box::new(handler),
+        };
+        let (mut context, mut state) = initialize(0);
+        let handlers_before = state.handlers.len();
+
+        let result = expect_ok(interpret(&ast, &mut context, &mut state));
+
+        assert!(matches!(result, Value::Tup(_)));
+        assert_eq!(state.handlers.len(), handlers_before);
+    }
+}